@@ -1,6 +1,9 @@
 use portaudio;
 use vorbis;
+use std::collections::VecDeque;
+use std::io;
 use std::sync::{mpsc, Mutex, Arc, Condvar, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 
 use metadata::TrackRef;
@@ -16,13 +19,20 @@ pub struct Player<'s> {
 
     #[allow(dead_code)]
     thread: thread::JoinGuard<'s, ()>,
+
+    // Builds staged decoders off the playback thread so the real-time loop
+    // never blocks on the preload's metadata / audio-key network I/O.
+    #[allow(dead_code)]
+    preload_thread: thread::JoinGuard<'s, ()>,
 }
 
 pub struct PlayerState {
     status: PlayStatus,
     position_ms: u32,
     position_measured_at: i64,
-    update_time: i64
+    update_time: i64,
+    end_of_track: bool,
+    volume: u16,
 }
 
 struct PlayerInternal<'s> {
@@ -30,6 +40,72 @@ struct PlayerInternal<'s> {
 
     session: &'s Session,
     commands: mpsc::Receiver<PlayerCommand>,
+    output: Box<AudioOutput + Send>,
+    decoder: Option<DecoderThread>,
+
+    // The track currently being built by the preload worker, if any, and the
+    // finished decoder once it is ready. Requesting a preload sends the id on
+    // `preload_tx`; the worker delivers `(id, decoder)` on `preload_rx`.
+    preloading: Option<SpotifyId>,
+    preload: Option<(SpotifyId, DecoderThread)>,
+    preload_tx: mpsc::Sender<SpotifyId>,
+    preload_rx: mpsc::Receiver<(SpotifyId, DecoderThread)>,
+
+    queue: VecDeque<SpotifyId>,
+}
+
+pub trait AudioOutput {
+    fn open(&mut self, sample_rate: f64, channels: u8);
+    fn start(&mut self);
+    fn stop(&mut self);
+    fn write(&mut self, data: &[i16]);
+}
+
+pub struct PortAudioOutput {
+    stream: Option<portaudio::stream::Stream<i16>>,
+}
+
+impl PortAudioOutput {
+    pub fn new() -> PortAudioOutput {
+        portaudio::initialize().unwrap();
+        PortAudioOutput { stream: None }
+    }
+}
+
+impl AudioOutput for PortAudioOutput {
+    fn open(&mut self, sample_rate: f64, channels: u8) {
+        self.stream = Some(portaudio::stream::Stream::<i16>::open_default(
+                0,
+                channels as i32,
+                sample_rate,
+                portaudio::stream::FRAMES_PER_BUFFER_UNSPECIFIED,
+                None
+                ).unwrap());
+    }
+
+    fn start(&mut self) {
+        self.stream.as_mut().unwrap().start().unwrap();
+    }
+
+    fn stop(&mut self) {
+        self.stream.as_mut().unwrap().stop().unwrap();
+    }
+
+    fn write(&mut self, data: &[i16]) {
+        match self.stream.as_mut().unwrap().write(data) {
+            Ok(_) => (),
+            Err(portaudio::PaError::OutputUnderflowed)
+                => eprintln!("Underflow"),
+            Err(e) => panic!("PA Error {}", e)
+        };
+    }
+}
+
+impl Drop for PortAudioOutput {
+    fn drop(&mut self) {
+        self.stream = None;
+        portaudio::terminate().unwrap();
+    }
 }
 
 enum PlayerCommand {
@@ -37,29 +113,157 @@ enum PlayerCommand {
     Play,
     Pause,
     Stop,
-    Seek(u32)
+    Seek(u32),
+    LoadNext(SpotifyId),
+    Volume(u16),
+    Preload(SpotifyId)
+}
+
+// Number of decoded Vorbis packets kept ahead of playback. Bounding the
+// channel turns it into a back-pressured ring buffer: the decoder thread
+// blocks once the playback thread is this many packets behind, which caps
+// memory use and the amount of audio we have to throw away on a seek.
+const BUFFER_SIZE: usize = 128;
+
+enum DecoderCommand {
+    Seek(f64),
+}
+
+// A decoded packet together with the epoch it was produced in and the
+// position it starts at. The epoch lets the playback thread drop packets
+// that were already in flight when a seek happened (see DecoderThread).
+struct DecodedPacket {
+    epoch: usize,
+    position_ms: u32,
+    data: Vec<i16>,
+}
+
+// Owns the decode chain on its own thread and feeds decoded samples into a
+// bounded channel. The playback thread only pops packets and hands them to
+// the AudioOutput, so control commands are no longer stuck behind a blocking
+// decode. The write to the output still runs on the playback thread, so
+// commands are serviced between writes.
+struct DecoderThread {
+    samples: mpsc::Receiver<DecodedPacket>,
+    commands: mpsc::Sender<DecoderCommand>,
+    epoch: Arc<AtomicUsize>,
+
+    #[allow(dead_code)]
+    thread: thread::JoinHandle<()>,
+}
+
+impl DecoderThread {
+    fn new<R>(mut decoder: vorbis::Decoder<R>) -> DecoderThread
+        where R: io::Read + io::Seek + Send + 'static {
+        let (sample_tx, sample_rx) = mpsc::sync_channel(BUFFER_SIZE);
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let epoch = Arc::new(AtomicUsize::new(0));
+
+        let thread = {
+            thread::spawn(move || {
+                // Count of seeks this thread has actually applied. It tracks
+                // the shared epoch that `seek()` bumps (one bump per queued
+                // Seek command), but is only advanced here, the instant a seek
+                // is serviced. Tagging packets with it instead of a read of the
+                // shared atomic means a packet decoded before a seek can never
+                // inherit the post-seek epoch, no matter when the bump lands.
+                let mut epoch = 0;
+
+                loop {
+                    // Only polled once per decode, so while we are blocked on a
+                    // full buffer a Seek waits until the buffer is drained. The
+                    // Seek handler drains the buffer itself, so the seek is
+                    // serviced even while the playback thread is paused.
+                    if let Ok(DecoderCommand::Seek(t)) = cmd_rx.try_recv() {
+                        decoder.time_seek(t).unwrap();
+                        epoch += 1;
+                    }
+
+                    // Measure before pulling the packet so this is the position
+                    // at which the packet starts.
+                    let position_ms = (decoder.time_tell().unwrap() * 1000f64) as u32;
+
+                    let data = match decoder.packets().next() {
+                        Some(Ok(packet)) => packet.data,
+                        Some(Err(vorbis::VorbisError::Hole)) => continue,
+                        Some(Err(e)) => panic!("Vorbis error {:?}", e),
+                        None => break,
+                    };
+
+                    let packet = DecodedPacket {
+                        epoch: epoch,
+                        position_ms: position_ms,
+                        data: data,
+                    };
+
+                    // A send error means the playback thread dropped us (Load,
+                    // Stop, or shutdown); there is nothing left to decode for.
+                    if sample_tx.send(packet).is_err() {
+                        break;
+                    }
+                }
+            })
+        };
+
+        DecoderThread {
+            samples: sample_rx,
+            commands: cmd_tx,
+            epoch: epoch,
+            thread: thread,
+        }
+    }
+
+    fn seek(&self, position_ms: u32) {
+        // Bump the epoch first so any packet still queued from before the seek
+        // is ignored by the playback thread, then tell the decoder to move.
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        self.commands.send(DecoderCommand::Seek(position_ms as f64 / 1000f64)).unwrap();
+    }
 }
 
 impl <'s> Player<'s> {
-    pub fn new(session: &Session) -> Player {
+    pub fn new(session: &Session, output: Box<AudioOutput + Send>) -> Player {
         let (cmd_tx, cmd_rx) = mpsc::channel();
+        let (preload_tx, preload_req_rx) = mpsc::channel::<SpotifyId>();
+        let (preload_res_tx, preload_rx) = mpsc::channel::<(SpotifyId, DecoderThread)>();
 
         let state = Arc::new((Mutex::new(PlayerState {
             status: PlayStatus::kPlayStatusStop,
             position_ms: 0,
             position_measured_at: 0,
             update_time: util::now_ms(),
+            end_of_track: false,
+            volume: 0xFFFF,
         }), Condvar::new()));
 
         let internal = PlayerInternal {
             session: session,
             commands: cmd_rx,
-            state: state.clone()
+            state: state.clone(),
+            output: output,
+            decoder: None,
+            preloading: None,
+            preload: None,
+            preload_tx: preload_tx,
+            preload_rx: preload_rx,
+            queue: VecDeque::new(),
         };
 
         Player {
             commands: cmd_tx,
             state: state,
+            // Resolve metadata + audio key and build the staged decoder here,
+            // off the playback thread. A finished decoder is handed back over
+            // the channel; a superseded request's decoder is simply dropped by
+            // the playback thread (non-blocking), never joined on it.
+            preload_thread: thread::scoped(move || {
+                for id in preload_req_rx.iter() {
+                    let decoder = PlayerInternal::build_decoder(session, id, 0);
+                    if preload_res_tx.send((id, decoder)).is_err() {
+                        break;
+                    }
+                }
+            }),
             thread: thread::scoped(move || {
                 internal.run()
             })
@@ -69,116 +273,223 @@ impl <'s> Player<'s> {
     fn command(&self, cmd: PlayerCommand) {
         self.commands.send(cmd).unwrap();
     }
-}
 
-impl <'s> PlayerInternal<'s> {
-    fn run(self) {
-        portaudio::initialize().unwrap();
+    // Queue a track to be played automatically once the current one ends.
+    pub fn load_next(&self, track: SpotifyId) {
+        self.command(PlayerCommand::LoadNext(track));
+    }
 
-        let stream = portaudio::stream::Stream::<i16>::open_default(
-                0,
-                2,
-                44100.0,
-                portaudio::stream::FRAMES_PER_BUFFER_UNSPECIFIED,
-                None
-                ).unwrap();
+    // Fetch the audio key and stage a decoder for the next track while the
+    // current one is still playing, so a following Load swaps in instantly.
+    pub fn preload(&self, track: SpotifyId) {
+        self.command(PlayerCommand::Preload(track));
+    }
+}
 
-        let mut decoder = None;
+impl <'s> PlayerInternal<'s> {
+    fn run(mut self) {
+        self.output.open(44100.0, 2);
 
         loop {
+            self.poll_preload();
+
             match self.commands.try_recv() {
                 Ok(PlayerCommand::Load(id, play, position)) => {
-                    println!("Load");
-                    let mut h = self.state.0.lock().unwrap();
-                    if h.status == PlayStatus::kPlayStatusPlay {
-                        stream.stop().unwrap();
+                    self.load_track(id, play, position);
+                }
+                Ok(PlayerCommand::LoadNext(id)) => {
+                    self.queue.push_back(id);
+                }
+                Ok(PlayerCommand::Preload(id)) => {
+                    // Ask the worker thread to stage this track while the
+                    // current one is still playing. Replacing an in-flight or
+                    // already-staged preload is just a new request: the stale
+                    // result is discarded when it arrives (see poll_preload),
+                    // so the playback thread never blocks on network I/O.
+                    let staged = self.preload.as_ref().map(|&(pid, _)| pid);
+                    if self.preloading != Some(id) && staged != Some(id) {
+                        self.preloading = Some(id);
+                        self.preload_tx.send(id).unwrap();
                     }
-                    h.status = PlayStatus::kPlayStatusLoading;
-                    h.position_ms = position;
-                    h.position_measured_at = util::now_ms();
-                    h.update_time = util::now_ms();
-                    drop(h);
-
-                    let track : TrackRef = self.session.metadata(id);
-                    let file_id = *track.wait().unwrap().files.first().unwrap();
-                    let key = self.session.audio_key(track.id(), file_id).into_inner();
-                    decoder = Some(
-                        vorbis::Decoder::new(
-                        Subfile::new(
-                        AudioDecrypt::new(key,
-                        self.session.audio_file(file_id)), 0xa7)).unwrap());
-                    decoder.as_mut().unwrap().time_seek(position as f64 / 1000f64).unwrap();
-
+                }
+                Ok(PlayerCommand::Volume(volume)) => {
                     let mut h = self.state.0.lock().unwrap();
-                    h.status = if play {
-                        stream.start().unwrap();
-                        PlayStatus::kPlayStatusPlay
-                    } else {
-                        PlayStatus::kPlayStatusPause
-                    };
-                    h.position_ms = position;
-                    h.position_measured_at = util::now_ms();
+                    h.volume = volume;
                     h.update_time = util::now_ms();
-                    println!("Load Done");
                 }
                 Ok(PlayerCommand::Seek(ms)) => {
-                    let mut h = self.state.0.lock().unwrap();
-                    decoder.as_mut().unwrap().time_seek(ms as f64 / 1000f64).unwrap();
-                    h.position_ms = (decoder.as_mut().unwrap().time_tell().unwrap() * 1000f64) as u32;
-                    h.position_measured_at = util::now_ms();
-                    h.update_time = util::now_ms();
+                    // No-op with no track loaded (e.g. after end of stream).
+                    if let Some(decoder) = self.decoder.as_ref() {
+                        let mut h = self.state.0.lock().unwrap();
+                        decoder.seek(ms);
+                        // Drain the now-stale buffer so the decoder thread,
+                        // which may be blocked on a full channel, wakes and
+                        // services the seek even while playback is paused.
+                        while let Ok(..) = decoder.samples.try_recv() {}
+                        h.position_ms = ms;
+                        h.position_measured_at = util::now_ms();
+                        h.update_time = util::now_ms();
+                    }
                 },
                 Ok(PlayerCommand::Play) => {
                     println!("Play");
-                    let mut h = self.state.0.lock().unwrap();
-                    h.status = PlayStatus::kPlayStatusPlay;
-                    h.update_time = util::now_ms();
-
-                    stream.start().unwrap();
+                    // Nothing to resume if the last track ran out and the queue
+                    // was empty; don't enter the play block with no decoder.
+                    if self.decoder.is_some() {
+                        let mut h = self.state.0.lock().unwrap();
+                        h.status = PlayStatus::kPlayStatusPlay;
+                        h.update_time = util::now_ms();
+
+                        self.output.start();
+                    }
                 },
                 Ok(PlayerCommand::Pause) => {
                     let mut h = self.state.0.lock().unwrap();
                     h.status = PlayStatus::kPlayStatusPause;
                     h.update_time = util::now_ms();
 
-                    stream.stop().unwrap();
+                    self.output.stop();
                 },
                 Ok(PlayerCommand::Stop) => {
                     let mut h = self.state.0.lock().unwrap();
                     if h.status == PlayStatus::kPlayStatusPlay {
-                        stream.stop().unwrap();
+                        self.output.stop();
                     }
 
                     h.status = PlayStatus::kPlayStatusPause;
                     h.update_time = util::now_ms();
-                    decoder = None;
+                    self.decoder = None;
                 },
                 Err(..) => (),
             }
 
-            if self.state.0.lock().unwrap().status == PlayStatus::kPlayStatusPlay {
-                match decoder.as_mut().unwrap().packets().next().unwrap() {
+            let playing = self.state.0.lock().unwrap().status == PlayStatus::kPlayStatusPlay;
+            if playing && self.decoder.is_some() {
+                match self.decoder.as_ref().unwrap().samples.try_recv() {
                     Ok(packet) => {
-                        match stream.write(&packet.data) {
-                            Ok(_) => (),
-                            Err(portaudio::PaError::OutputUnderflowed)
-                                => eprintln!("Underflow"),
-                            Err(e) => panic!("PA Error {}", e)
-                        };
+                        // Drop packets that were decoded before the most
+                        // recent seek; they belong to a stale position.
+                        if packet.epoch == self.decoder.as_ref().unwrap().epoch.load(Ordering::SeqCst) {
+                            let mut h = self.state.0.lock().unwrap();
+                            let volume = h.volume;
+                            h.position_ms = packet.position_ms;
+                            h.position_measured_at = util::now_ms();
+                            drop(h);
+
+                            if volume == 0xFFFF {
+                                // Full volume is a no-op; skip the copy.
+                                self.output.write(&packet.data);
+                            } else {
+                                // Apply volume in software by scaling each
+                                // sample; the factor is <= 1 so this never clips.
+                                let factor = volume as f32 / 65535.0;
+                                let scaled: Vec<i16> = packet.data.iter()
+                                    .map(|s| (*s as f32 * factor).round() as i16)
+                                    .collect();
+                                self.output.write(&scaled);
+                            }
+                        }
                     },
-                    Err(vorbis::VorbisError::Hole) => (),
-                    Err(e) => panic!("Vorbis error {:?}", e)
+                    // No frames decoded yet; yield briefly so we keep
+                    // servicing commands without spinning the CPU.
+                    Err(mpsc::TryRecvError::Empty) => thread::sleep_ms(10),
+                    // The decoder thread closed the channel: the track ran to
+                    // its end. Advance the queue if we can, otherwise stop.
+                    Err(mpsc::TryRecvError::Disconnected) => self.end_of_track(),
+                }
+            }
+        }
+    }
+
+    fn load_track(&mut self, id: SpotifyId, play: bool, position: u32) {
+        println!("Load");
+        let mut h = self.state.0.lock().unwrap();
+        if h.status == PlayStatus::kPlayStatusPlay {
+            self.output.stop();
+        }
+        h.status = PlayStatus::kPlayStatusLoading;
+        h.position_ms = position;
+        h.position_measured_at = util::now_ms();
+        h.update_time = util::now_ms();
+        h.end_of_track = false;
+        drop(h);
+
+        // Pick up anything the preload worker has already finished, then reuse
+        // a staged decoder if it matches. A non-matching staged decoder is
+        // dropped here, which is non-blocking (it just tears down its own
+        // decode thread); we never wait on the worker's network I/O.
+        self.poll_preload();
+        self.preloading = None;
+        let decoder = match self.preload.take() {
+            Some((pid, decoder)) if pid == id => {
+                // Staged decoders start at the beginning, so seek forward if a
+                // non-zero position was requested.
+                if position != 0 {
+                    decoder.seek(position);
                 }
+                decoder
+            }
+            _ => Self::build_decoder(self.session, id, position),
+        };
+        self.decoder = Some(decoder);
+
+        let mut h = self.state.0.lock().unwrap();
+        h.status = if play {
+            self.output.start();
+            PlayStatus::kPlayStatusPlay
+        } else {
+            PlayStatus::kPlayStatusPause
+        };
+        h.position_ms = position;
+        h.position_measured_at = util::now_ms();
+        h.update_time = util::now_ms();
+        println!("Load Done");
+    }
 
-                let mut h = self.state.0.lock().unwrap();
-                h.position_ms = (decoder.as_mut().unwrap().time_tell().unwrap() * 1000f64) as u32;
-                h.position_measured_at = util::now_ms();
+    fn poll_preload(&mut self) {
+        // Collect any decoders the worker has finished. A result we no longer
+        // want (the preload was replaced or consumed) is dropped here without
+        // blocking the playback thread.
+        while let Ok((id, decoder)) = self.preload_rx.try_recv() {
+            if self.preloading == Some(id) {
+                self.preloading = None;
+                self.preload = Some((id, decoder));
             }
         }
+    }
+
+    fn build_decoder(session: &Session, id: SpotifyId, position: u32) -> DecoderThread {
+        let track : TrackRef = session.metadata(id);
+        let file_id = *track.wait().unwrap().files.first().unwrap();
+        let key = session.audio_key(track.id(), file_id).into_inner();
+        let mut decoder =
+            vorbis::Decoder::new(
+            Subfile::new(
+            AudioDecrypt::new(key,
+            session.audio_file(file_id)), 0xa7)).unwrap();
+        decoder.time_seek(position as f64 / 1000f64).unwrap();
+        DecoderThread::new(decoder)
+    }
 
-        drop(stream);
+    fn end_of_track(&mut self) {
+        self.output.stop();
+        self.decoder = None;
 
-        portaudio::terminate().unwrap();
+        {
+            let mut h = self.state.0.lock().unwrap();
+            h.status = PlayStatus::kPlayStatusStop;
+            h.end_of_track = true;
+            h.update_time = util::now_ms();
+        }
+        // Wake anyone blocked in wait_update (e.g. the spirc controller) so it
+        // can react to the track having finished.
+        self.state.1.notify_all();
+
+        // Gapless advance: if the controller queued a follow-up track, start
+        // playing it straight away without a round-trip stall.
+        if let Some(next) = self.queue.pop_front() {
+            self.load_track(next, true, 0);
+        }
     }
 }
 
@@ -206,6 +517,15 @@ impl <'s> SpircDelegate for Player<'s> {
         self.command(PlayerCommand::Seek(position_ms));
     }
 
+    fn end_of_track(&self) -> bool {
+        self.state.0.lock().unwrap().end_of_track
+    }
+
+    // Set the playback volume (0 = silent, 0xFFFF = unattenuated).
+    fn volume(&self, volume: u16) {
+        self.command(PlayerCommand::Volume(volume));
+    }
+
     fn state(&self) -> MutexGuard<Self::State> {
         self.state.0.lock().unwrap()
     }
@@ -228,5 +548,10 @@ impl SpircState for PlayerState {
     fn update_time(&self) -> i64 {
         return self.update_time;
     }
+
+    fn volume(&self) -> u16 {
+        return self.volume;
+    }
 }
 
+